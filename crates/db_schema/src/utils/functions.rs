@@ -0,0 +1,29 @@
+// These are plain SQL function bindings (backed by `create function`
+// statements: `controversy_rank`/`wilson_rank` in the `ranking_functions`
+// migration, `hot_rank` in an earlier one) and don't reference any table's
+// columns, so they don't depend
+// on `schema.rs` knowing about `controversy_rank`/`wilson_rank`/
+// `hot_rank_updated`. `update_hot_ranks` in `scheduled_tasks.rs` writes those
+// columns through raw `sql_query` rather than the typed query DSL, so this
+// ranking subsystem doesn't need a `schema.rs` regen to compile either.
+use diesel::sql_types::{BigInt, Double, Timestamp};
+
+diesel::define_sql_function! {
+  /// Sorts posts/comments/communities by a time-decayed score, so new
+  /// content with a few votes can still outrank old content with many.
+  fn hot_rank(score: BigInt, time: Timestamp) -> Double;
+}
+
+diesel::define_sql_function! {
+  /// Sorts content by how evenly split its votes are: lots of both
+  /// upvotes and downvotes ranks higher than a lopsided vote count of the
+  /// same size.
+  fn controversy_rank(upvotes: BigInt, downvotes: BigInt) -> Double;
+}
+
+diesel::define_sql_function! {
+  /// Wilson score lower bound at 95% confidence, used to rank comments by
+  /// "best" rather than raw score: a 10-1 upvote ratio backed by only
+  /// eleven votes ranks lower than the same ratio backed by hundreds.
+  fn wilson_rank(upvotes: BigInt, downvotes: BigInt) -> Double;
+}
@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+
+/// <https://nodeinfo.diaspora.software/ns/schema/2.1>
+///
+/// Kept compatible with 2.0 responses too: nodeinfo versions only ever add
+/// fields, so a 2.0 document still deserializes fine here, it just leaves
+/// the 2.1-only fields `None`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NodeInfo {
+  pub version: String,
+  pub software: Option<NodeInfoSoftware>,
+  pub protocols: Option<Vec<String>>,
+  pub usage: Option<NodeInfoUsage>,
+  pub open_registrations: Option<bool>,
+  /// Added in 2.1. Free-form per-instance metadata (eg `nodeName`); not used
+  /// by lemmy itself, but kept so the type still matches peers that send it.
+  pub metadata: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NodeInfoSoftware {
+  pub name: Option<String>,
+  pub version: Option<String>,
+  /// Added in 2.1
+  pub repository: Option<String>,
+  /// Added in 2.1
+  pub homepage: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NodeInfoUsage {
+  pub users: Option<NodeInfoUsers>,
+  pub local_posts: Option<i64>,
+  pub local_comments: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NodeInfoUsers {
+  pub total: Option<i64>,
+}
+
+/// The document served at `/.well-known/nodeinfo`, used to discover which
+/// nodeinfo schema versions an instance supports and where to fetch them,
+/// since not every server publishes `nodeinfo/2.0.json` at the path Lemmy
+/// assumes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WellKnownNodeInfo {
+  pub links: Vec<WellKnownNodeInfoLink>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WellKnownNodeInfoLink {
+  pub rel: String,
+  pub href: String,
+}
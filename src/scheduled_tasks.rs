@@ -1,30 +1,27 @@
 use clokwerk::{Scheduler, TimeUnits as CTimeUnits};
 use diesel::{
   dsl::{now, IntervalDsl},
-  Connection,
-  ExpressionMethods,
-  QueryDsl,
+  sql_types::{Integer, Nullable, Text},
+  Connection, ExpressionMethods, QueryDsl, QueryableByName,
 };
 // Import week days and WeekDay
 use diesel::{sql_query, PgConnection, RunQueryDsl};
-use lemmy_db_schema::{
-  schema::{
-    activity,
-    comment_aggregates,
-    community_aggregates,
-    community_person_ban,
-    instance,
-    person,
-    post_aggregates,
-  },
-  source::instance::{Instance, InstanceForm},
-  utils::{functions::hot_rank, naive_now},
-};
-use lemmy_routes::nodeinfo::NodeInfo;
+use futures::stream::{self, StreamExt};
+use lemmy_db_schema::schema::{activity, community_person_ban, person};
+use lemmy_routes::nodeinfo::{NodeInfo, WellKnownNodeInfo};
 use lemmy_utils::{error::LemmyError, REQWEST_TIMEOUT};
-use reqwest::blocking::Client;
+use reqwest::Client as AsyncClient;
 use std::{thread, time::Duration};
 use tracing::info;
+use url::Url;
+
+/// Default number of days of federation activity to retain, used when
+/// `LEMMY_ACTIVITY_RETENTION_DAYS` is unset.
+const DEFAULT_ACTIVITY_RETENTION_DAYS: i32 = 180;
+
+/// Number of rows deleted per batch in `clear_old_activities`, to avoid
+/// long-running transactions and WAL bloat on large instances.
+const ACTIVITY_DELETE_BATCH_SIZE: i64 = 10_000;
 
 /// Schedules various cleanup tasks for lemmy in a background thread
 pub fn setup(db_url: String, user_agent: String) -> Result<(), LemmyError> {
@@ -36,11 +33,21 @@ pub fn setup(db_url: String, user_agent: String) -> Result<(), LemmyError> {
   let mut conn_3 = PgConnection::establish(&db_url).expect("could not establish connection");
   let mut conn_4 = PgConnection::establish(&db_url).expect("could not establish connection");
 
+  // A misconfigured `0` or negative value would make `clear_old_activities`'s
+  // cutoff `>= now` and delete the entire activity table, so anything that
+  // isn't a sane positive retention period falls back to the default instead
+  // of being trusted verbatim.
+  let activity_retention_days = std::env::var("LEMMY_ACTIVITY_RETENTION_DAYS")
+    .ok()
+    .and_then(|s| s.parse::<i32>().ok())
+    .filter(|days| *days > 0)
+    .unwrap_or(DEFAULT_ACTIVITY_RETENTION_DAYS);
+
   // Run on startup
   active_counts(&mut conn_1);
   update_hot_ranks(&mut conn_1, false);
   update_banned_when_expired(&mut conn_1);
-  clear_old_activities(&mut conn_1);
+  clear_old_activities(&mut conn_1, activity_retention_days);
 
   // Update active counts every hour
   scheduler.every(CTimeUnits::hour(1)).run(move || {
@@ -55,7 +62,7 @@ pub fn setup(db_url: String, user_agent: String) -> Result<(), LemmyError> {
 
   // Clear old activities every week
   scheduler.every(CTimeUnits::weeks(1)).run(move || {
-    clear_old_activities(&mut conn_3);
+    clear_old_activities(&mut conn_3, activity_retention_days);
   });
 
   scheduler.every(CTimeUnits::days(1)).run(move || {
@@ -69,59 +76,96 @@ pub fn setup(db_url: String, user_agent: String) -> Result<(), LemmyError> {
   }
 }
 
-/// Update the hot_rank columns for the aggregates tables
+/// Updates the sort-score columns for the aggregates tables: the existing
+/// time-decay `hot_rank` (and `hot_rank_active`), a `controversy_rank`
+/// driven by the up/down vote split (posts and comments), and a
+/// Wilson-score `wilson_rank` for comments that favors a confidently-good
+/// upvote ratio over a raw score. Keeping all of these in sync in the same
+/// pass means new sort modes don't need their own batch job.
+///
+/// `hot_rank`/`hot_rank_active` decay with `published`/
+/// `newest_comment_time_necro` even when the underlying score hasn't
+/// changed, so they're always recomputed for every row in scope - that
+/// decay is the whole reason this job runs every 5 minutes.
+/// `controversy_rank`/`wilson_rank` don't decay with time though, so they're
+/// only recomputed when `hot_rank_updated is null` (a trigger clears it back
+/// to null whenever `upvotes`/`downvotes` change). Both updates are folded
+/// into a single `update` per table so a row still only gets written once
+/// per pass, rather than doubling write amplification with a second
+/// statement for the vote ranks.
 fn update_hot_ranks(conn: &mut PgConnection, last_week_only: bool) {
-  let mut post_update = diesel::update(post_aggregates::table).into_boxed();
-  let mut comment_update = diesel::update(comment_aggregates::table).into_boxed();
-  let mut community_update = diesel::update(community_aggregates::table).into_boxed();
-
-  // Only update for the last week of content
-  if last_week_only {
+  let scope = if last_week_only {
     info!("Updating hot ranks for last week...");
-    let last_week = now - diesel::dsl::IntervalDsl::weeks(1);
-
-    post_update = post_update.filter(post_aggregates::published.gt(last_week));
-    comment_update = comment_update.filter(comment_aggregates::published.gt(last_week));
-    community_update = community_update.filter(community_aggregates::published.gt(last_week));
+    "where published > now() - interval '1 week'"
   } else {
     info!("Updating hot ranks for all history...");
-  }
-
-  post_update
-    .set((
-      post_aggregates::hot_rank.eq(hot_rank(post_aggregates::score, post_aggregates::published)),
-      post_aggregates::hot_rank_active.eq(hot_rank(
-        post_aggregates::score,
-        post_aggregates::newest_comment_time_necro,
-      )),
-    ))
-    .execute(conn)
-    .expect("update post_aggregate hot_ranks");
+    ""
+  };
+
+  sql_query(format!(
+    "update post_aggregates set
+       hot_rank = hot_rank(score, published),
+       hot_rank_active = hot_rank(score, newest_comment_time_necro),
+       controversy_rank = case when hot_rank_updated is null
+         then controversy_rank(upvotes, downvotes) else controversy_rank end,
+       hot_rank_updated = case when hot_rank_updated is null then now() else hot_rank_updated end
+     {}",
+    scope
+  ))
+  .execute(conn)
+  .expect("update post_aggregate hot_ranks");
+
+  sql_query(format!(
+    "update comment_aggregates set
+       hot_rank = hot_rank(score, published),
+       controversy_rank = case when hot_rank_updated is null
+         then controversy_rank(upvotes, downvotes) else controversy_rank end,
+       wilson_rank = case when hot_rank_updated is null
+         then wilson_rank(upvotes, downvotes) else wilson_rank end,
+       hot_rank_updated = case when hot_rank_updated is null then now() else hot_rank_updated end
+     {}",
+    scope
+  ))
+  .execute(conn)
+  .expect("update comment_aggregate hot_ranks");
 
-  comment_update
-    .set(comment_aggregates::hot_rank.eq(hot_rank(
-      comment_aggregates::score,
-      comment_aggregates::published,
-    )))
-    .execute(conn)
-    .expect("update comment_aggregate hot_ranks");
+  sql_query(format!(
+    "update community_aggregates set hot_rank = hot_rank(subscribers, published) {}",
+    scope
+  ))
+  .execute(conn)
+  .expect("update community_aggregate hot_ranks");
 
-  community_update
-    .set(community_aggregates::hot_rank.eq(hot_rank(
-      community_aggregates::subscribers,
-      community_aggregates::published,
-    )))
-    .execute(conn)
-    .expect("update community_aggregate hot_ranks");
   info!("Done.");
 }
 
-/// Clear old activities (this table gets very large)
-fn clear_old_activities(conn: &mut PgConnection) {
-  info!("Clearing old activities...");
-  diesel::delete(activity::table.filter(activity::published.lt(now - 6.months())))
+/// Clear old activities (this table gets very large). Deletes in bounded
+/// batches rather than a single statement, so the job doesn't hold a long
+/// transaction or bloat the WAL on large instances.
+fn clear_old_activities(conn: &mut PgConnection, retention_days: i32) {
+  info!("Clearing activities older than {} days...", retention_days);
+  let cutoff = now - retention_days.days();
+
+  loop {
+    let deleted_rows = diesel::delete(
+      activity::table.filter(
+        activity::id.eq_any(
+          activity::table
+            .select(activity::id)
+            .filter(activity::published.lt(cutoff))
+            .limit(ACTIVITY_DELETE_BATCH_SIZE),
+        ),
+      ),
+    )
     .execute(conn)
     .expect("clear old activities");
+
+    if deleted_rows == 0 {
+      break;
+    }
+
+    thread::sleep(Duration::from_millis(100));
+  }
   info!("Done.");
 }
 
@@ -129,26 +173,18 @@ fn clear_old_activities(conn: &mut PgConnection) {
 fn active_counts(conn: &mut PgConnection) {
   info!("Updating active site and community aggregates ...");
 
-  let intervals = vec![
-    ("1 day", "day"),
-    ("1 week", "week"),
-    ("1 month", "month"),
-    ("6 months", "half_year"),
-  ];
+  let intervals =
+    vec![("1 day", "day"), ("1 week", "week"), ("1 month", "month"), ("6 months", "half_year")];
 
   for i in &intervals {
     let update_site_stmt = format!(
       "update site_aggregates set users_active_{} = (select * from site_aggregates_activity('{}'))",
       i.1, i.0
     );
-    sql_query(update_site_stmt)
-      .execute(conn)
-      .expect("update site stats");
+    sql_query(update_site_stmt).execute(conn).expect("update site stats");
 
     let update_community_stmt = format!("update community_aggregates ca set users_active_{} = mv.count_ from community_aggregates_activity('{}') mv where ca.community_id = mv.community_id_", i.1, i.0);
-    sql_query(update_community_stmt)
-      .execute(conn)
-      .expect("update community stats");
+    sql_query(update_community_stmt).execute(conn).expect("update community stats");
   }
 
   info!("Done.");
@@ -158,57 +194,183 @@ fn active_counts(conn: &mut PgConnection) {
 fn update_banned_when_expired(conn: &mut PgConnection) {
   info!("Updating banned column if it expires ...");
 
-  diesel::update(
-    person::table
-      .filter(person::banned.eq(true))
-      .filter(person::ban_expires.lt(now)),
-  )
-  .set(person::banned.eq(false))
-  .execute(conn)
-  .expect("update person.banned when expires");
+  diesel::update(person::table.filter(person::banned.eq(true)).filter(person::ban_expires.lt(now)))
+    .set(person::banned.eq(false))
+    .execute(conn)
+    .expect("update person.banned when expires");
 
   diesel::delete(community_person_ban::table.filter(community_person_ban::expires.lt(now)))
     .execute(conn)
     .expect("remove community_ban expired rows");
 }
 
-/// Updates the instance software and version
+/// How many instances to probe for nodeinfo at once.
+const INSTANCE_PROBE_CONCURRENCY: usize = 10;
+
+/// Caps the exponential backoff applied to instances that keep failing, so a
+/// long-dead instance is still re-checked occasionally (here, at most every
+/// 64 days) rather than abandoned forever.
+const MAX_BACKOFF_DOUBLINGS: i32 = 6;
+
+/// An instance that is due for a nodeinfo probe this pass, either because it
+/// has never been checked or because its exponential backoff window elapsed.
+#[derive(QueryableByName)]
+struct DueInstance {
+  #[diesel(sql_type = diesel::sql_types::Int4)]
+  id: i32,
+  #[diesel(sql_type = diesel::sql_types::Text)]
+  domain: String,
+}
+
+/// Nodeinfo schema rels we understand, ordered from least to most preferred,
+/// so 2.1 is picked over 2.0 when an instance advertises both.
+const SUPPORTED_NODEINFO_RELS: [&str; 2] = [
+  "http://nodeinfo.diaspora.software/ns/schema/2.0",
+  "http://nodeinfo.diaspora.software/ns/schema/2.1",
+];
+
+/// The outcome of probing an instance for nodeinfo, kept distinct from a
+/// bare `Option` so a peer that's merely incompatible with the schemas we
+/// understand isn't recorded the same way as one we couldn't reach at all -
+/// only the latter should count against `consecutive_failures`.
+enum NodeInfoProbeResult {
+  Found(NodeInfo),
+  /// We got a response, but it wasn't a nodeinfo schema we understand (no
+  /// matching `.well-known/nodeinfo` link, or a malformed document). The
+  /// instance is alive, just not (yet) speaking something we can parse.
+  Incompatible,
+  /// Couldn't establish a connection at all.
+  Unreachable,
+}
+
+/// Discovers and fetches an instance's nodeinfo document via
+/// `/.well-known/nodeinfo`, rather than assuming it's hosted at
+/// `/nodeinfo/2.0.json`. Picks the highest schema version the instance
+/// advertises that we understand, and follows its `href`.
+///
+/// The `href` is peer-controlled, so it's validated to be an `https` URL on
+/// the same `domain` we just queried before it's fetched - otherwise an
+/// instance could advertise an internal/loopback address and turn this probe
+/// into SSRF against our own network.
+async fn fetch_node_info(client: &AsyncClient, domain: &str) -> NodeInfoProbeResult {
+  let well_known_url = format!("https://{}/.well-known/nodeinfo", domain);
+  let well_known_res = match client.get(&well_known_url).send().await {
+    Ok(res) => res,
+    Err(_) => return NodeInfoProbeResult::Unreachable,
+  };
+
+  let Ok(well_known) = well_known_res.json::<WellKnownNodeInfo>().await else {
+    return NodeInfoProbeResult::Incompatible;
+  };
+
+  let Some(node_info_url) = well_known
+    .links
+    .iter()
+    .filter_map(|link| {
+      SUPPORTED_NODEINFO_RELS
+        .iter()
+        .position(|rel| *rel == link.rel)
+        .map(|preference| (preference, &link.href))
+    })
+    .max_by_key(|(preference, _)| *preference)
+    .map(|(_, href)| href.clone())
+  else {
+    return NodeInfoProbeResult::Incompatible;
+  };
+
+  let Ok(node_info_url) = Url::parse(&node_info_url) else {
+    return NodeInfoProbeResult::Incompatible;
+  };
+  if node_info_url.scheme() != "https" || node_info_url.host_str() != Some(domain) {
+    return NodeInfoProbeResult::Incompatible;
+  }
+
+  let node_info_res = match client.get(node_info_url).send().await {
+    Ok(res) => res,
+    Err(_) => return NodeInfoProbeResult::Unreachable,
+  };
+
+  match node_info_res.json::<NodeInfo>().await {
+    Ok(node_info) => NodeInfoProbeResult::Found(node_info),
+    Err(_) => NodeInfoProbeResult::Incompatible,
+  }
+}
+
+/// Updates the instance software and version.
+///
+/// Instances are probed concurrently (bounded by `INSTANCE_PROBE_CONCURRENCY`)
+/// instead of one at a time, so a single slow or unreachable host can't stall
+/// the whole pass. Each instance also tracks `last_checked` and
+/// `consecutive_failures`, which are used to back off exponentially on
+/// instances that keep failing instead of retrying them every single day.
 fn update_instance_software(conn: &mut PgConnection, user_agent: &str) {
   info!("Updating instances software and versions...");
 
-  let client = Client::builder()
+  let due_instances = sql_query(format!(
+    "select id, domain from instance
+     where last_checked is null
+        or last_checked < now() - (power(2, least(consecutive_failures, {})) * interval '1 day')",
+    MAX_BACKOFF_DOUBLINGS
+  ))
+  .get_results::<DueInstance>(conn)
+  .expect("load instances due for a nodeinfo check");
+
+  let client = AsyncClient::builder()
     .user_agent(user_agent)
     .timeout(REQWEST_TIMEOUT)
     .build()
     .expect("couldnt build reqwest client");
 
-  let instances = instance::table
-    .get_results::<Instance>(conn)
-    .expect("no instances found");
-
-  for instance in instances {
-    let node_info_url = format!("https://{}/nodeinfo/2.0.json", instance.domain);
+  let runtime = tokio::runtime::Runtime::new().expect("could not start tokio runtime");
+  let results: Vec<(i32, NodeInfoProbeResult)> = runtime.block_on(async {
+    stream::iter(due_instances)
+      .map(|due| {
+        let client = client.clone();
+        async move {
+          let result = fetch_node_info(&client, &due.domain).await;
+          (due.id, result)
+        }
+      })
+      .buffer_unordered(INSTANCE_PROBE_CONCURRENCY)
+      .collect()
+      .await
+  });
 
-    // Skip it if it can't connect
-    let res = client
-      .get(&node_info_url)
-      .send()
-      .ok()
-      .and_then(|t| t.json::<NodeInfo>().ok());
-
-    if let Some(node_info) = res {
-      let software = node_info.software.as_ref();
-      let form = InstanceForm::builder()
-        .domain(instance.domain)
-        .software(software.and_then(|s| s.name.clone()))
-        .version(software.and_then(|s| s.version.clone()))
-        .updated(Some(naive_now()))
-        .build();
-
-      diesel::update(instance::table.find(instance.id))
-        .set(form)
+  for (instance_id, result) in results {
+    match result {
+      NodeInfoProbeResult::Found(node_info) => {
+        let software = node_info.software.as_ref();
+        sql_query(
+          "update instance
+           set software = $1, version = $2, updated = now(), last_checked = now(), consecutive_failures = 0
+           where id = $3",
+        )
+        .bind::<Nullable<Text>, _>(software.and_then(|s| s.name.clone()))
+        .bind::<Nullable<Text>, _>(software.and_then(|s| s.version.clone()))
+        .bind::<Integer, _>(instance_id)
         .execute(conn)
         .expect("update site instance software");
+      }
+      // The instance responded, just not with a schema we understand - it's
+      // alive, so don't count it towards consecutive_failures/backoff.
+      NodeInfoProbeResult::Incompatible => {
+        sql_query(
+          "update instance set last_checked = now(), consecutive_failures = 0 where id = $1",
+        )
+        .bind::<Integer, _>(instance_id)
+        .execute(conn)
+        .expect("record instance as reachable but incompatible");
+      }
+      NodeInfoProbeResult::Unreachable => {
+        sql_query(
+          "update instance
+           set last_checked = now(), consecutive_failures = consecutive_failures + 1
+           where id = $1",
+        )
+        .bind::<Integer, _>(instance_id)
+        .execute(conn)
+        .expect("record instance probe failure");
+      }
     }
   }
   info!("Done.");